@@ -1,51 +1,152 @@
 /// A utility that processes a batch of transactions and outputs the final state of accounts
 /// that were affected by these transactions.
 ///
-/// Usage: cargo run -- transactions.csv > accounts.csv
+/// Usage:
+///     cargo run -- transactions.csv [shard_count] > accounts.csv
+///     cargo run -- serve 127.0.0.1:7878
 ///
+/// `shard_count`, if given and greater than 1, splits processing across that many worker
+/// threads by client id instead of running single-threaded. `serve` instead starts a
+/// long-running server that keeps a persistent TxProcessor in memory for all connections.
 use std::{env, io};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use csv::Trim;
-use crate::tx_processor::{Transaction, TxProcessor, BoxResult};
-use std::io::Write;
+use crate::tx_processor::{Account, Transaction, TransactionRecord, TxProcessor, BoxResult};
+use std::io::{BufRead, BufReader, Write};
 
 mod tx_processor;
 
 fn main() -> BoxResult<()> {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Unexpected number of arguments: {}. Provide exactly 1 argument", args.len() - 1);
-        println!("\tUsage: cargo run -- transactions.csv > accounts.csv");
+    if args.len() == 3 && args[1] == "serve" {
+        return run_server(&args[2]);
+    }
+    if args.len() < 2 || args.len() > 3 {
+        println!("Unexpected number of arguments: {}. Provide 1 or 2 arguments", args.len() - 1);
+        println!("\tUsage: cargo run -- transactions.csv [shard_count] > accounts.csv");
+        println!("\t       cargo run -- serve 127.0.0.1:7878");
         std::process::exit(exitcode::USAGE);
     }
 
-    let processed = run_processing(args.get(1).unwrap())?;
-    print_serialized(processed)?;
+    let path = args.get(1).unwrap();
+    let shard_count: usize = match args.get(2) {
+        Some(raw) => match raw.parse() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                println!("shard_count must be a positive integer, got: {}", raw);
+                std::process::exit(exitcode::USAGE);
+            }
+        },
+        None => 1,
+    };
+
+    let accounts = if shard_count > 1 {
+        run_processing_sharded(path, shard_count)?
+    } else {
+        run_processing(path)?.into_accounts()
+    };
+    print_serialized(&accounts, io::stdout())?;
 
     return Ok(());
 }
 
-/// Continuously read from file, parse lines to structs and send it to tx processor
+/// Parse one CSV record and feed it to the processor. Malformed or invalid records are skipped
+/// (and logged to stderr) rather than aborting the whole run; shared by the file and socket paths.
+fn ingest_record(tx_processor: &mut TxProcessor, result: csv::Result<TransactionRecord>) -> BoxResult<()> {
+    let record = match result {
+        Ok(record) => record,
+        Err(e) => {
+            eprintln!("Skipping malformed record: {}", e);
+            return Ok(());
+        }
+    };
+    let tx = match Transaction::try_from(record) {
+        Ok(tx) => tx,
+        Err(e) => {
+            eprintln!("Skipping invalid record: {}", e);
+            return Ok(());
+        }
+    };
+    tx_processor.process_tx(tx)
+}
+
+/// Continuously read from file, parse lines to structs and send it to tx processor.
 fn run_processing(path: &str) -> BoxResult<TxProcessor> {
-    let mut reader = csv::ReaderBuilder::new().trim(Trim::All).from_path(path)?;
+    let mut reader = csv::ReaderBuilder::new().trim(Trim::All).flexible(true).from_path(path)?;
     let mut tx_processor = TxProcessor::new();
 
     for result in reader.deserialize() {
-        let tx: Transaction = result?; // parsing fails if a record is malformed
-        tx_processor.process_tx(tx)?;
+        ingest_record(&mut tx_processor, result)?;
     }
 
     return Ok(tx_processor);
 }
 
-/// Traverse map with accounts and print them as a cvs
-fn print_serialized(tx_processor: TxProcessor) -> BoxResult<()> {
+/// Split transactions across `shard_count` worker threads by client id, so each client's
+/// transactions are always handled by the same thread and in order, then merge the resulting
+/// account maps (the shards own disjoint sets of clients, so there's nothing to reconcile).
+fn run_processing_sharded(path: &str, shard_count: usize) -> BoxResult<HashMap<u16, Account>> {
+    let mut receivers = Vec::with_capacity(shard_count);
+    let senders: Vec<_> = (0..shard_count).map(|_| {
+        let (tx, rx) = mpsc::channel::<Transaction>();
+        receivers.push(rx);
+        tx
+    }).collect();
+
+    let workers: Vec<_> = receivers.into_iter().map(|rx| {
+        thread::spawn(move || {
+            let mut tx_processor = TxProcessor::new();
+            for tx in rx {
+                tx_processor.process_tx(tx).expect("processing a transaction failed");
+            }
+            tx_processor.into_accounts()
+        })
+    }).collect();
+
+    {
+        let mut reader = csv::ReaderBuilder::new().trim(Trim::All).flexible(true).from_path(path)?;
+
+        for result in reader.deserialize() {
+            let record: TransactionRecord = match result {
+                Ok(record) => record,
+                Err(e) => {
+                    eprintln!("Skipping malformed record: {}", e);
+                    continue;
+                }
+            };
+            let tx = match Transaction::try_from(record) {
+                Ok(tx) => tx,
+                Err(e) => {
+                    eprintln!("Skipping invalid record: {}", e);
+                    continue;
+                }
+            };
+            senders[tx.client() as usize % shard_count].send(tx)?;
+        }
+
+        drop(senders); // close every worker's channel so its `for tx in rx` loop can end
+    }
+
+    let mut accounts = HashMap::new();
+    for worker in workers {
+        accounts.extend(worker.join().expect("worker thread panicked"));
+    }
+    return Ok(accounts);
+}
+
+/// Traverse map with accounts and print them as a cvs, shared by the file and socket paths
+fn print_serialized<W: Write>(accounts: &HashMap<u16, Account>, mut out: W) -> BoxResult<()> {
     // csv/serde lib throws an error when trying to serialize with prepended headers
-    io::stdout().write_all(b"client,available,held,total,locked\n")?;
+    out.write_all(b"client,available,held,total,locked\n")?;
 
     let mut wtr = csv::WriterBuilder::new()
-        .has_headers(false).from_writer(io::stdout());
+        .has_headers(false).from_writer(out);
 
-    for (_i, account) in tx_processor.get_accounts() {
+    for (_i, account) in accounts {
         wtr.serialize(account)?;
     }
 
@@ -53,9 +154,68 @@ fn print_serialized(tx_processor: TxProcessor) -> BoxResult<()> {
     Ok(())
 }
 
+/// Listen on `addr` and serve a persistent in-memory TxProcessor shared by every connection, so
+/// transactions can arrive continuously instead of as a single batch file.
+fn run_server(addr: &str) -> BoxResult<()> {
+    let listener = TcpListener::bind(addr)?;
+    let tx_processor = Arc::new(Mutex::new(TxProcessor::new()));
+    println!("Listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Skipping connection that failed to accept: {}", e);
+                continue;
+            }
+        };
+        let tx_processor = Arc::clone(&tx_processor);
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, tx_processor) {
+                eprintln!("Connection error: {}", e);
+            }
+        });
+    }
+
+    return Ok(());
+}
+
+/// Feed one connection's `type,client,tx,amount` rows into the shared processor. A `QUERY` line
+/// instead replies with the current accounts snapshot, in the same format `print_serialized`
+/// writes for the batch file path. The snapshot is rendered to a buffer while the lock is held
+/// and written to the socket only after releasing it, so a slow reader on one connection can't
+/// stall transaction ingestion on every other connection.
+fn handle_connection(stream: TcpStream, tx_processor: Arc<Mutex<TxProcessor>>) -> BoxResult<()> {
+    let mut writer = stream.try_clone()?;
+    let reader = BufReader::new(stream);
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().eq_ignore_ascii_case("query") {
+            let snapshot = {
+                let guard = tx_processor.lock().unwrap();
+                let mut buf = Vec::new();
+                print_serialized(guard.get_accounts(), &mut buf)?;
+                buf
+            };
+            writer.write_all(&snapshot)?;
+            continue;
+        }
+
+        let mut rdr = csv::ReaderBuilder::new()
+            .has_headers(false).trim(Trim::All).flexible(true).from_reader(line.as_bytes());
+        let mut guard = tx_processor.lock().unwrap();
+        for result in rdr.deserialize() {
+            ingest_record(&mut guard, result)?;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::run_processing;
+    use crate::{run_processing, run_processing_sharded};
 
     #[test]
     fn acc_two_ignores_invalid_withdrawal() {
@@ -152,4 +312,44 @@ mod tests {
         assert_eq!("9.80, 0.0, 9.80, false", accounts.get(&2).unwrap().to_string());
         assert_eq!("33.123456, 0.000000, 33.123456, false", accounts.get(&3).unwrap().to_string());
     }
+
+    #[test]
+    // rows with a missing amount, an unparseable amount, or an unknown tx type are skipped;
+    // the remaining valid deposit still goes through
+    fn skips_malformed_and_unknown_type_records() {
+        let res = run_processing("test_data/013.csv").unwrap();
+        let accounts = res.get_accounts();
+        assert_eq!(1, accounts.len());
+        assert_eq!("1.0, 0, 1.0, false", accounts.get(&1).unwrap().to_string());
+    }
+
+    #[test]
+    // a second dispute after a resolve is a no-op: the tx is no longer `Processed`, so the
+    // funds stay available rather than being re-held
+    fn redisputing_a_resolved_tx_is_noop() {
+        let res = run_processing("test_data/014.csv").unwrap();
+        let accounts = res.get_accounts();
+        assert_eq!(1, accounts.len());
+        assert_eq!("5.0, 0, 5.0, false", accounts.get(&1).unwrap().to_string());
+    }
+
+    #[test]
+    // a second dispute after a chargeback is a no-op: the tx is no longer `Processed`, so no
+    // further funds move on the now-locked account
+    fn redisputing_a_charged_back_tx_is_noop() {
+        let res = run_processing("test_data/015.csv").unwrap();
+        let accounts = res.get_accounts();
+        assert_eq!(1, accounts.len());
+        assert_eq!("0.0, 0.0, 0.0, true", accounts.get(&1).unwrap().to_string());
+    }
+
+    #[test]
+    fn many_acc_with_transfers_sharded_matches_serial() {
+        let serial = run_processing("test_data/009.csv").unwrap().into_accounts();
+        let sharded = run_processing_sharded("test_data/009.csv", 4).unwrap();
+        assert_eq!(serial.len(), sharded.len());
+        for (client, account) in &serial {
+            assert_eq!(account.to_string(), sharded.get(client).unwrap().to_string());
+        }
+    }
 }
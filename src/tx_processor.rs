@@ -1,16 +1,15 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use serde::{Deserialize, Serializer};
 use serde::Serialize;
 use bigdecimal::{BigDecimal, Zero};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::str::FromStr;
 use core::fmt;
-use crate::tx_processor::TxKind::Deposit;
 
 pub type BoxResult<T> = Result<T, Box<dyn Error>>;
 
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, PartialEq)]
 enum TxKind {
     Withdrawal,
     Deposit,
@@ -19,13 +18,103 @@ enum TxKind {
     Chargeback,
 }
 
+impl FromStr for TxKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "withdrawal" => Ok(TxKind::Withdrawal),
+            "deposit" => Ok(TxKind::Deposit),
+            "dispute" => Ok(TxKind::Dispute),
+            "resolve" => Ok(TxKind::Resolve),
+            "chargeback" => Ok(TxKind::Chargeback),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where a disputable transaction currently stands. A `dispute` only takes effect from
+/// `Processed`, a `resolve` only from `Disputed`, and a `chargeback` only from `Disputed`;
+/// any other transition is a no-op rather than an error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TxState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+/// Raw shape of a CSV row, before the amount column is validated against its transaction type.
+/// `dispute`/`resolve`/`chargeback` rows legitimately carry no amount, so it stays optional here.
 #[derive(Debug, Deserialize)]
+pub struct TransactionRecord {
+    #[serde(rename = "type")]
+    tx_type: String,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
+}
+
+/// Why a `TransactionRecord` couldn't be turned into a `Transaction`.
+#[derive(Debug)]
+pub enum ParseError {
+    MissingAmount,
+    BadAmount(String),
+    UnknownType(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::MissingAmount => write!(f, "missing amount for a deposit/withdrawal"),
+            ParseError::BadAmount(raw) => write!(f, "unparseable amount: {}", raw),
+            ParseError::UnknownType(raw) => write!(f, "unknown transaction type: {}", raw),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
 pub struct Transaction {
-    #[serde(rename(serialize = "type", deserialize = "type"))]
     tx_type: TxKind,
     client: u16,
     tx: u32,
-    amount: String,
+    amount: BigDecimal,
+}
+
+impl Transaction {
+    /// The client this transaction belongs to, e.g. to shard work across worker threads
+    pub fn client(&self) -> u16 {
+        self.client
+    }
+}
+
+impl TryFrom<TransactionRecord> for Transaction {
+    type Error = ParseError;
+
+    fn try_from(record: TransactionRecord) -> Result<Self, Self::Error> {
+        let TransactionRecord { tx_type, client, tx, amount } = record;
+        let tx_type = TxKind::from_str(tx_type.as_str())
+            .map_err(|_| ParseError::UnknownType(tx_type))?;
+
+        let amount = match tx_type {
+            TxKind::Withdrawal | TxKind::Deposit => {
+                let raw = amount.filter(|s| !s.trim().is_empty())
+                    .ok_or(ParseError::MissingAmount)?;
+                BigDecimal::from_str(raw.trim()).map_err(|_| ParseError::BadAmount(raw))?
+            },
+            TxKind::Dispute | TxKind::Resolve | TxKind::Chargeback => BigDecimal::zero(),
+        };
+
+        Ok(Transaction { tx_type, client, tx, amount })
+    }
+}
+
+/// A deposit's client and parsed amount, kept around so a later dispute/resolve/chargeback can
+/// look it up by `tx` id without retaining every transaction ever seen.
+struct Deposit {
+    client: u16,
+    amount: BigDecimal,
 }
 
 /// Turn a BigDecimal into a rounded &str
@@ -43,8 +132,6 @@ pub struct Account {
     #[serde(serialize_with = "serialize_bigdec")]
     total: BigDecimal,
     locked: bool,
-    #[serde(skip_serializing)]
-    disputed: HashSet<u32>,
 }
 
 impl fmt::Display for Account {
@@ -59,18 +146,16 @@ impl fmt::Display for Account {
 
 pub struct TxProcessor {
     accounts: HashMap<u16, Account>,
-    transactions: HashMap<u32, Transaction>,
-}
-
-fn get_bigdec(str: String) -> BoxResult<BigDecimal> {
-    Ok(BigDecimal::from_str(str.as_str())?)
+    deposits: HashMap<u32, Deposit>,
+    tx_states: HashMap<u32, TxState>,
 }
 
 impl TxProcessor {
     pub fn new() -> Self {
         TxProcessor {
             accounts: HashMap::new(),
-            transactions: HashMap::new(),
+            deposits: HashMap::new(),
+            tx_states: HashMap::new(),
         }
     }
 
@@ -78,10 +163,15 @@ impl TxProcessor {
         &self.accounts
     }
 
+    /// Consume the processor and hand back its accounts, e.g. to merge several shards' results
+    pub fn into_accounts(self) -> HashMap<u16, Account> {
+        self.accounts
+    }
+
     pub fn process_tx(&mut self, tx: Transaction) -> BoxResult<()> {
-        match tx.tx_type { // make sure amount is defined and non-negative for types with amount
+        match tx.tx_type { // make sure amount is non-negative for types with amount
             TxKind::Withdrawal | TxKind::Deposit => {
-                if BigDecimal::from_str(tx.amount.as_str())? < BigDecimal::zero() {
+                if tx.amount < BigDecimal::zero() {
                     return Ok(()); // ignore records with negative amounts
                 }
             },
@@ -103,9 +193,10 @@ impl TxProcessor {
         if account.locked {
             return Ok(());
         }
-        account.available += BigDecimal::from_str(tx.amount.as_str())?;
-        account.total += BigDecimal::from_str(tx.amount.as_str())?;
-        self.transactions.insert(tx.tx, tx);
+        account.available += &tx.amount;
+        account.total += &tx.amount;
+        self.tx_states.insert(tx.tx, TxState::Processed);
+        self.deposits.insert(tx.tx, Deposit { client: tx.client, amount: tx.amount });
         Ok(())
     }
 
@@ -115,29 +206,27 @@ impl TxProcessor {
         if account.locked {
             return Ok(());
         }
-        let tx_amount = BigDecimal::from_str(tx.amount.as_str())?;
-        if account.available >= tx_amount {
-            account.available -= BigDecimal::from_str(tx.amount.as_str())?;
-            account.total -= BigDecimal::from_str(tx.amount.as_str())?;
+        if account.available >= tx.amount {
+            account.available -= &tx.amount;
+            account.total -= &tx.amount;
         }
-        self.transactions.insert(tx.tx, tx);
         Ok(())
     }
 
     /// File a dispute over a deposit transaction
     fn dispute(&mut self, tx: Transaction) -> BoxResult<()> {
-        if !self.is_tx_valid(tx.client, tx.tx) || !self.is_deposit(tx.tx) {
+        if !self.is_tx_valid(tx.client, tx.tx) {
             return Ok(())
         }
-        let disputed_amount = match self.get_tx_amount(tx.tx)? {
+        if self.tx_states.get(&tx.tx) != Some(&TxState::Processed) {
+            return Ok(()); // only a tx that hasn't been disputed yet can be disputed
+        }
+        let disputed_amount = match self.get_tx_amount(tx.tx) {
             Some(amount) => amount,
             None => return Ok(()),
         };
+        self.tx_states.insert(tx.tx, TxState::Disputed);
         let account = self.get_account(tx.client);
-        if account.disputed.contains(&tx.tx) {
-            return Ok(()) // already being disputed, nothing to do
-        }
-        account.disputed.insert(tx.tx);
         account.held += &disputed_amount;
         account.available -= disputed_amount;
         Ok(())
@@ -148,15 +237,15 @@ impl TxProcessor {
         if !self.is_tx_valid(tx.client, tx.tx) {
             return Ok(());
         }
-        let disputed_amount = match self.get_tx_amount(tx.tx)? {
+        if self.tx_states.get(&tx.tx) != Some(&TxState::Disputed) {
+            return Ok(()); // ignoring trying to resolve a tx that isn't currently disputed
+        }
+        let disputed_amount = match self.get_tx_amount(tx.tx) {
             Some(amount) => amount,
             None => return Ok(()),
         };
+        self.tx_states.insert(tx.tx, TxState::Resolved);
         let account = self.get_account(tx.client);
-        if !account.disputed.contains(&tx.tx) {
-            return Ok(()); // ignoring trying to resolve undisputed tx
-        }
-        account.disputed.remove(&tx.tx);
         account.held -= &disputed_amount;
         account.available += &disputed_amount;
         Ok(())
@@ -167,46 +256,35 @@ impl TxProcessor {
         if !self.is_tx_valid(tx.client, tx.tx) {
             return Ok(());
         }
-        let disputed_amount = match self.get_tx_amount(tx.tx)? {
+        if self.tx_states.get(&tx.tx) != Some(&TxState::Disputed) {
+            return Ok(()); // ignoring trying to charge back a tx that isn't currently disputed
+        }
+        let disputed_amount = match self.get_tx_amount(tx.tx) {
             Some(amount) => amount,
             None => return Ok(()),
         };
-        let mut account = self.get_account(tx.client);
-        if account.locked {
+        if self.accounts.get(&tx.client).is_some_and(|account| account.locked) {
             return Ok(());
         }
-        if !account.disputed.contains(&tx.tx) {
-            return Ok(()); // ignoring trying to resolve undisputed tx
-        }
+        self.tx_states.insert(tx.tx, TxState::ChargedBack);
+        let account = self.get_account(tx.client);
         account.locked = true;
-        account.disputed.remove(&tx.tx);
         account.held -= &disputed_amount;
         account.total -= &disputed_amount;
         Ok(())
     }
 
-    /// Validate that reference transaction exists and that its client is the same as the client of
-    /// the current transactions
+    /// Validate that a disputed deposit exists and that its client is the same as the client of
+    /// the current transaction
     fn is_tx_valid(&self, client: u16, ref_tx: u32) -> bool {
-        match self.transactions.get(&ref_tx) {
-            Some(tx) => tx.client == client,
-            None => false
-        }
-    }
-
-    fn is_deposit(&self, tx_id: u32) -> bool {
-        match self.transactions.get(&tx_id) {
-            Some(tx) => tx.tx_type == Deposit,
+        match self.deposits.get(&ref_tx) {
+            Some(deposit) => deposit.client == client,
             None => false
         }
     }
 
-    fn get_tx_amount(&self, tx_id: u32) -> BoxResult<Option<BigDecimal>> {
-        let tx = self.transactions.get(&tx_id);
-        match tx {
-            Some(tx) => Ok(Some(get_bigdec(tx.amount.clone())?)),
-            None => Ok(None),
-        }
+    fn get_tx_amount(&self, tx_id: u32) -> Option<BigDecimal> {
+        self.deposits.get(&tx_id).map(|deposit| deposit.amount.clone())
     }
 
     /// Get an existing account or create an empty account
@@ -216,7 +294,6 @@ impl TxProcessor {
             available: BigDecimal::zero(),
             held: BigDecimal::zero(),
             total: BigDecimal::zero(),
-            disputed: HashSet::new(),
             locked: false,
         })
     }
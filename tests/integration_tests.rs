@@ -1,5 +1,7 @@
 use assert_cmd::prelude::*;
-use std::process::Command;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 
 #[test]
 pub fn invalid_args() -> Result<(), Box<dyn std::error::Error>> {
@@ -29,3 +31,26 @@ pub fn correctly_formatted_output() -> Result<(), Box<dyn std::error::Error>> {
                "client,available,held,total,locked\n1,-1.0,1.0,0.0,false\n");
     Ok(())
 }
+
+#[test]
+pub fn serve_accepts_transactions_and_answers_query() -> Result<(), Box<dyn std::error::Error>> {
+    let mut server = Command::cargo_bin("tx_processor")?
+        .arg("serve").arg("127.0.0.1:0")
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut banner = String::new();
+    BufReader::new(server.stdout.take().unwrap()).read_line(&mut banner)?;
+    let addr = banner.trim_start_matches("Listening on ").trim();
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"deposit,1,1,5.0\nwithdrawal,1,2,1.0\nquery\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    server.kill()?;
+
+    assert_eq!(response, "client,available,held,total,locked\n1,4.0,0,4.0,false\n");
+    Ok(())
+}